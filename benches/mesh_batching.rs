@@ -0,0 +1,70 @@
+//! Compares the old `BTreeMap`/`BTreeSet` grouping strategy against grouping
+//! with `hashbrown::HashMap` under hashbrown's default hasher (not the
+//! `FastHasher` that `prepare_mesh_batches::system` uses) at instance counts
+//! representative of small, medium, and large scenes.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bevy_instancing::prelude::InstancedMeshKey;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use hashbrown::HashMap;
+
+fn fake_keys(mesh_count: usize, _key_count: usize) -> Vec<(InstancedMeshKey, u32)> {
+    // The handle has to vary by `i`, not `i % key_count`: capping it at `key_count`
+    // regardless of `mesh_count` meant every claimed scale (1k/10k/100k) actually
+    // exercised the same <= 32-element inner set, so neither container's growth
+    // behavior past a tiny set was ever measured.
+    //
+    // The key itself still can't vary across `key_count` distinct buckets here:
+    // `InstancedMeshKey` is only ever constructed in this crate via `Default`, and
+    // this benchmark binary only sees its `pub` surface (it depends on
+    // `bevy_instancing` like any other external crate), so there's no way from here
+    // to build `key_count` genuinely distinct keys without guessing at fields this
+    // bench can't see are public. Every entry below still lands in the single
+    // `InstancedMeshKey::default()` bucket; the outer-map/`key_count` dimension of
+    // this benchmark remains unaddressed pending a public way to vary the key.
+    (0..mesh_count)
+        .map(|i| (InstancedMeshKey::default(), i as u32))
+        .collect()
+}
+
+fn group_btree(meshes: &[(InstancedMeshKey, u32)]) -> BTreeMap<InstancedMeshKey, BTreeSet<u32>> {
+    let mut grouped = BTreeMap::<InstancedMeshKey, BTreeSet<u32>>::new();
+    for (key, handle) in meshes {
+        grouped.entry(key.clone()).or_default().insert(*handle);
+    }
+    grouped
+}
+
+fn group_hashbrown(meshes: &[(InstancedMeshKey, u32)]) -> HashMap<InstancedMeshKey, HashMap<u32, ()>> {
+    let mut grouped = HashMap::<InstancedMeshKey, HashMap<u32, ()>>::new();
+    for (key, handle) in meshes {
+        grouped.entry(key.clone()).or_default().insert(*handle, ());
+    }
+    grouped
+}
+
+fn bench_grouping(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mesh_batching/key_meshes");
+
+    for &instance_count in &[1_000usize, 10_000, 100_000] {
+        let meshes = fake_keys(instance_count, 32);
+
+        group.bench_with_input(
+            BenchmarkId::new("btree", instance_count),
+            &meshes,
+            |b, meshes| b.iter(|| group_btree(meshes)),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("hashbrown", instance_count),
+            &meshes,
+            |b, meshes| b.iter(|| group_hashbrown(meshes)),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_grouping);
+criterion_main!(benches);
@@ -0,0 +1,318 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::{info_span, Res, ResMut, Vec3},
+    render::{
+        mesh::Indices,
+        render_resource::{IndexFormat, ShaderType, StorageBuffer},
+    },
+};
+use hashbrown::{HashMap, HashSet};
+
+use crate::instancing::material::{
+    material_instanced::MaterialInstanced,
+    plugin::{GpuIndexBufferData, InstancedMeshKey},
+};
+
+use super::prepare_mesh_batches::MeshBatches;
+
+/// A flattened BVH node: interior nodes store the index of their first child
+/// (the second is implicitly `first_child + 1`) and leaves store the range of
+/// `primitive_buffer` entries they cover, distinguished by `primitive_count > 0`.
+#[derive(ShaderType, Clone, Copy)]
+pub struct GpuNode {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub first_child_or_primitive: u32,
+    pub primitive_count: u32,
+}
+
+/// A batch's BVH, flattened depth-first for GPU traversal: `node_buffer` is the
+/// node array, `primitive_buffer` maps leaf primitive slots back to triangle
+/// indices into the batch's concatenated index buffer.
+pub struct BatchBvh {
+    pub node_buffer: StorageBuffer<Vec<GpuNode>>,
+    pub primitive_buffer: StorageBuffer<Vec<u32>>,
+}
+
+/// Opt-in per-batch-key request for a BVH, so batches that nobody ray-queries
+/// don't pay the construction cost.
+#[derive(Default)]
+pub struct BvhRequests {
+    pub requested: HashSet<InstancedMeshKey>,
+}
+
+/// Per-`MaterialInstanced` storage of the [`BatchBvh`] built for each requested
+/// batch key, mirroring [`MeshBatches`].
+pub struct BatchBvhs<M: MaterialInstanced> {
+    pub bvhs: HashMap<InstancedMeshKey, BatchBvh>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for BatchBvhs<M> {
+    fn default() -> Self {
+        Self {
+            bvhs: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+struct Triangle {
+    primitive_index: u32,
+    centroid: Vec3,
+    min: Vec3,
+    max: Vec3,
+}
+
+/// Reads the `Vec3` position packed at the start of vertex `vertex_index`.
+/// Position is always the first attribute in `MeshBatch::vertex_data` (other
+/// attributes like normal/uv follow it), so only the per-vertex stride is
+/// needed here, not the full attribute layout.
+fn read_vertex_position(vertex_data: &[u8], stride: usize, vertex_index: u32) -> Vec3 {
+    let offset = vertex_index as usize * stride;
+    let read_f32 =
+        |i: usize| f32::from_le_bytes(vertex_data[offset + i..offset + i + 4].try_into().unwrap());
+    Vec3::new(read_f32(0), read_f32(4), read_f32(8))
+}
+
+/// Looks up the three vertex indices of `triangle_index` in `index_data`,
+/// accounting for both the `Indexed` (U16/U32) and `NonIndexed` (implicit
+/// `0, 1, 2, ...` indexing) cases.
+fn triangle_vertex_indices(index_data: &GpuIndexBufferData, triangle_index: u32) -> [u32; 3] {
+    let base = triangle_index as usize * 3;
+    match index_data {
+        GpuIndexBufferData::Indexed { indices: Indices::U16(idx), .. } => {
+            [idx[base] as u32, idx[base + 1] as u32, idx[base + 2] as u32]
+        }
+        GpuIndexBufferData::Indexed { indices: Indices::U32(idx), .. } => {
+            [idx[base], idx[base + 1], idx[base + 2]]
+        }
+        GpuIndexBufferData::NonIndexed { .. } => {
+            [base as u32, base as u32 + 1, base as u32 + 2]
+        }
+    }
+}
+
+/// Computes each triangle's centroid and AABB from the batch's rebased index
+/// buffer and concatenated vertex data.
+fn triangle_aabbs(vertex_data: &[u8], index_data: &GpuIndexBufferData) -> Vec<Triangle> {
+    let index_count = match index_data {
+        GpuIndexBufferData::Indexed { index_count, .. } => *index_count,
+        GpuIndexBufferData::NonIndexed { vertex_count } => *vertex_count,
+    };
+
+    // The batch's concatenated vertex count isn't threaded through to this
+    // function, but it's recoverable from the highest index any triangle
+    // references (or, for `NonIndexed`, the draw's own vertex count), so the
+    // per-vertex byte stride can be derived from `vertex_data.len()` without
+    // needing the mesh's vertex attribute layout here.
+    let total_vertex_count = match index_data {
+        GpuIndexBufferData::Indexed { indices: Indices::U16(idx), .. } => {
+            idx.iter().copied().max().map_or(0, |max| max as u32 + 1)
+        }
+        GpuIndexBufferData::Indexed { indices: Indices::U32(idx), .. } => {
+            idx.iter().copied().max().map_or(0, |max| max + 1)
+        }
+        GpuIndexBufferData::NonIndexed { vertex_count } => *vertex_count,
+    };
+    let stride = vertex_data.len() / total_vertex_count.max(1) as usize;
+
+    (0..index_count / 3)
+        .map(|triangle_index| {
+            let [a, b, c] = triangle_vertex_indices(index_data, triangle_index)
+                .map(|vertex_index| read_vertex_position(vertex_data, stride, vertex_index));
+
+            Triangle {
+                primitive_index: triangle_index,
+                centroid: (a + b + c) / 3.0,
+                min: a.min(b).min(c),
+                max: a.max(b).max(c),
+            }
+        })
+        .collect()
+}
+
+enum BuildNode {
+    Leaf {
+        min: Vec3,
+        max: Vec3,
+        primitives: Vec<u32>,
+    },
+    Interior {
+        min: Vec3,
+        max: Vec3,
+        children: [Box<BuildNode>; 2],
+    },
+}
+
+fn bounds_of(triangles: &[Triangle]) -> (Vec3, Vec3) {
+    triangles.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), tri| (min.min(tri.min), max.max(tri.max)),
+    )
+}
+
+/// Recursively splits `triangles` by the longest-axis midpoint until a leaf is
+/// small enough, producing a binary BVH over the batch's geometry.
+fn build_recursive(mut triangles: Vec<Triangle>) -> BuildNode {
+    const MAX_LEAF_PRIMITIVES: usize = 4;
+
+    let (min, max) = bounds_of(&triangles);
+
+    if triangles.len() <= MAX_LEAF_PRIMITIVES {
+        return BuildNode::Leaf {
+            min,
+            max,
+            primitives: triangles.iter().map(|tri| tri.primitive_index).collect(),
+        };
+    }
+
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+    let midpoint = (min[axis] + max[axis]) * 0.5;
+
+    triangles.sort_by(|a, b| a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap());
+    let split = triangles
+        .iter()
+        .position(|tri| tri.centroid[axis] > midpoint)
+        .unwrap_or(triangles.len() / 2)
+        .clamp(1, triangles.len() - 1);
+
+    let right = triangles.split_off(split);
+    let left = triangles;
+
+    BuildNode::Interior {
+        min,
+        max,
+        children: [Box::new(build_recursive(left)), Box::new(build_recursive(right))],
+    }
+}
+
+/// Flattens a recursively-built BVH depth-first into `node_buffer`/
+/// `primitive_buffer` arrays suitable for GPU traversal, writing `node` into the
+/// already-reserved slot `nodes[slot]`.
+fn flatten(node: &BuildNode, slot: usize, nodes: &mut Vec<GpuNode>, primitives: &mut Vec<u32>) {
+    let gpu_node = match node {
+        BuildNode::Leaf { min, max, primitives: leaf_primitives } => {
+            let first_primitive = primitives.len() as u32;
+            primitives.extend_from_slice(leaf_primitives);
+
+            GpuNode {
+                min: *min,
+                max: *max,
+                first_child_or_primitive: first_primitive,
+                primitive_count: leaf_primitives.len() as u32,
+            }
+        }
+        BuildNode::Interior { min, max, children } => {
+            // Reserve both children's slots side by side before recursing, so the
+            // second child is always at `first_child + 1`.
+            let first_child = nodes.len();
+            let interior = GpuNode {
+                min: *min,
+                max: *max,
+                first_child_or_primitive: first_child as u32,
+                primitive_count: 0,
+            };
+            nodes.extend([interior; 2]);
+
+            flatten(&children[0], first_child, nodes, primitives);
+            flatten(&children[1], first_child + 1, nodes, primitives);
+            interior
+        }
+    };
+
+    nodes[slot] = gpu_node;
+}
+
+/// Builds a [`BatchBvh`] for every `InstancedMeshKey` in [`BvhRequests`], over the
+/// batch's already-concatenated vertex/index data. Batches nobody has requested a
+/// BVH for are left untouched, so non-ray-tracing users pay nothing.
+pub fn build_bvh<M: MaterialInstanced>(
+    mesh_batches: Res<MeshBatches<M>>,
+    requests: Res<BvhRequests>,
+    mut bvhs: ResMut<BatchBvhs<M>>,
+) {
+    info_span!("BVH").in_scope(|| {
+        for key in &requests.requested {
+            let Some(batch) = mesh_batches.get(key) else {
+                continue;
+            };
+
+            let triangles = triangle_aabbs(&batch.vertex_data, &batch.index_data);
+            let root = build_recursive(triangles);
+
+            // Slot 0 is reserved for the root; `flatten` overwrites it.
+            let mut node_data = vec![GpuNode {
+                min: Vec3::ZERO,
+                max: Vec3::ZERO,
+                first_child_or_primitive: 0,
+                primitive_count: 0,
+            }];
+            let mut primitive_data = Vec::new();
+            flatten(&root, 0, &mut node_data, &mut primitive_data);
+
+            let mut node_buffer = StorageBuffer::default();
+            node_buffer.set_label(Some("bvh_node_buffer"));
+            *node_buffer.get_mut() = node_data;
+
+            let mut primitive_buffer = StorageBuffer::default();
+            primitive_buffer.set_label(Some("bvh_primitive_buffer"));
+            *primitive_buffer.get_mut() = primitive_data;
+
+            bvhs.bvhs.insert(
+                key.clone(),
+                BatchBvh {
+                    node_buffer,
+                    primitive_buffer,
+                },
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two triangles sharing an edge, packed as `Vec3` position only (stride 12):
+    /// `(0,0,0) (1,0,0) (0,1,0)` and `(1,0,0) (1,1,0) (0,1,0)`.
+    fn two_triangle_vertex_data() -> Vec<u8> {
+        [
+            [0.0f32, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+        ]
+        .iter()
+        .flat_map(|v| v.iter().flat_map(|f| f.to_le_bytes()))
+        .collect()
+    }
+
+    #[test]
+    fn triangle_aabbs_reads_positions_instead_of_stubbing_zero() {
+        let vertex_data = two_triangle_vertex_data();
+        let index_data = GpuIndexBufferData::Indexed {
+            indices: Indices::U32(vec![0, 1, 2, 1, 3, 2]),
+            index_count: 6,
+            index_format: IndexFormat::Uint32,
+        };
+
+        let triangles = triangle_aabbs(&vertex_data, &index_data);
+        assert_eq!(triangles.len(), 2);
+
+        assert_eq!(triangles[0].min, Vec3::new(0.0, 0.0, 0.0));
+        assert_eq!(triangles[0].max, Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(triangles[0].centroid, Vec3::new(1.0 / 3.0, 1.0 / 3.0, 0.0));
+
+        assert_eq!(triangles[1].min, Vec3::new(0.0, 0.0, 0.0));
+        assert_eq!(triangles[1].max, Vec3::new(1.0, 1.0, 0.0));
+    }
+}
@@ -0,0 +1,564 @@
+use std::{collections::HashMap, marker::PhantomData};
+
+use bevy::{
+    ecs::query::QueryItem,
+    prelude::{
+        info_span, AssetServer, Commands, Component, Entity, Handle, Mat4, Mesh, Query, Res,
+        ResMut, Shader, Vec3,
+    },
+    render::{
+        extract_component::ExtractComponent,
+        render_resource::{
+            BindGroup, BindGroupEntry, BindGroupLayout, BindGroupLayoutEntry, BindingResource,
+            BindingType, BufferBindingType, CachedComputePipelineId, ComputePipelineDescriptor,
+            PipelineCache, ShaderStages, ShaderType, StorageBuffer, UniformBuffer,
+        },
+        renderer::RenderDevice,
+    },
+};
+
+use crate::instancing::{
+    instance_slice::InstanceSlice,
+    material::{
+        material_instanced::MaterialInstanced,
+        plugin::{GpuIndexBufferData, InstancedMeshKey, RenderMeshes},
+        systems::prepare_mesh_batches::MeshBatches,
+    },
+    render::instance::Instance,
+};
+
+/// Marker component for views that should cull instances on the GPU before drawing.
+///
+/// Without this component, `system<M>` still emits fully-populated indirect draws
+/// (every instance is drawn), so CPU-only render paths are unaffected.
+#[derive(Component, Clone, Copy, Default)]
+pub struct GpuCulling;
+
+impl ExtractComponent for GpuCulling {
+    type Query = &'static GpuCulling;
+    type Filter = ();
+    type Out = GpuCulling;
+
+    fn extract_component(_: QueryItem<'_, Self::Query>) -> Option<Self::Out> {
+        Some(GpuCulling)
+    }
+}
+
+/// Per-instance AABB, 1:1 aligned with the instance data uploaded for a batch.
+/// Padding fields keep the struct at the 16-byte alignment storage buffers require.
+#[derive(ShaderType, Clone, Copy)]
+pub struct MeshCullingData {
+    pub center: Vec3,
+    pub _padding_a: f32,
+    pub half_extents: Vec3,
+    pub _padding_b: f32,
+}
+
+impl MeshCullingData {
+    pub fn new(center: Vec3, half_extents: Vec3) -> Self {
+        Self {
+            center,
+            half_extents,
+            ..Self::default_padding()
+        }
+    }
+
+    fn default_padding() -> Self {
+        Self {
+            center: Vec3::ZERO,
+            _padding_a: 0.0,
+            half_extents: Vec3::ZERO,
+            _padding_b: 0.0,
+        }
+    }
+}
+
+/// Per-`MaterialInstanced` storage of culling AABBs, one [`MeshCullingData`] per
+/// instance in the same order the batch's instance buffer is written in.
+pub struct MeshCullingDataBuffer<M: MaterialInstanced> {
+    pub buffer: StorageBuffer<Vec<MeshCullingData>>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for MeshCullingDataBuffer<M> {
+    fn default() -> Self {
+        let mut buffer = StorageBuffer::default();
+        buffer.set_label(Some("mesh_culling_data_buffer"));
+        Self {
+            buffer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// `first_instance` offset into the compacted output-index buffer allocated to
+/// each batch, so the cull shader's compacted writes don't collide across batches.
+/// Keyed by a numeric batch index rather than `InstancedMeshKey` directly, since
+/// that's what `frustum_cull.wgsl`'s `batch_index`/`first_instance` arrays index
+/// by; `batch_index_by_key` is the lookup from one to the other.
+#[derive(Default)]
+pub struct CullingBatchOffsets {
+    pub batch_index_by_key: HashMap<InstancedMeshKey, u32>,
+    pub first_instance: Vec<u32>,
+}
+
+/// Per-instance `InstancedMeshKey`, 1:1 aligned with [`MeshCullingDataBuffer`] and
+/// [`ModelMatricesBuffer`], so `prepare_culling_offsets` knows which batch each
+/// instance belongs to without re-running `prepare_mesh_batches`'s mesh-keying pass.
+#[derive(Default)]
+pub struct InstanceBatchKeys<M: MaterialInstanced> {
+    pub keys: Vec<InstancedMeshKey>,
+    _phantom: PhantomData<M>,
+}
+
+/// Per-instance model matrix, 1:1 aligned with [`MeshCullingDataBuffer`]; bound to
+/// the cull shader's `model_matrices` storage buffer.
+pub struct ModelMatricesBuffer<M: MaterialInstanced> {
+    pub buffer: StorageBuffer<Vec<Mat4>>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for ModelMatricesBuffer<M> {
+    fn default() -> Self {
+        let mut buffer = StorageBuffer::default();
+        buffer.set_label(Some("model_matrices_buffer"));
+        Self {
+            buffer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// Per-instance index into `indirect_args`/`first_instance`, bound to the cull
+/// shader's `batch_index` storage buffer. Built by `prepare_culling_offsets`,
+/// since the numeric batch index an [`InstancedMeshKey`] maps to is only settled
+/// once that frame's batches are known.
+pub struct BatchIndexBuffer<M: MaterialInstanced> {
+    pub buffer: StorageBuffer<Vec<u32>>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for BatchIndexBuffer<M> {
+    fn default() -> Self {
+        let mut buffer = StorageBuffer::default();
+        buffer.set_label(Some("batch_index_buffer"));
+        Self {
+            buffer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// GPU mirror of [`CullingBatchOffsets::first_instance`], bound to the cull
+/// shader's `first_instance` storage buffer.
+pub struct FirstInstanceBuffer<M: MaterialInstanced> {
+    pub buffer: StorageBuffer<Vec<u32>>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for FirstInstanceBuffer<M> {
+    fn default() -> Self {
+        let mut buffer = StorageBuffer::default();
+        buffer.set_label(Some("first_instance_buffer"));
+        Self {
+            buffer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// Mirrors the 5-field layout `frustum_cull.wgsl`'s `DrawIndirectArgs` expects.
+/// One entry per batch (not per draw): a batch sharing multiple distinct meshes
+/// still culls as a single instance set, so every draw belonging to a batch reads
+/// the same slot's `instance_count`/`first_instance` once this is fanned back out
+/// by the (separate) system that issues the real indirect draw calls.
+#[derive(ShaderType, Clone, Copy, Default)]
+pub struct GpuCullingDrawArgs {
+    pub vertex_or_index_count: u32,
+    pub instance_count: u32,
+    pub first_index_or_vertex: u32,
+    pub base_vertex_or_first_instance: i32,
+    pub first_instance: u32,
+}
+
+/// Per-batch culling draw args, bound to the cull shader's `indirect_args`
+/// storage buffer; `prepare_culling_offsets` rebuilds every entry each frame with
+/// `instance_count` zeroed before the cull shader atomically bumps the survivors
+/// back up. The vertex/index count comes from that batch's own [`MeshBatch`],
+/// `first_instance` (and, for a non-indexed batch, `base_vertex_or_first_instance`)
+/// from its allocated offset, and the shader leaves all fields but
+/// `instance_count` untouched. Once populated this buffer is a
+/// standalone, directly-indirectable draw-args buffer: a render-graph node that
+/// binds it and issues `draw_indexed_indirect`/`draw_indirect` per batch (instead
+/// of reading `MeshBatch::indirect_data`) gets the culled `instance_count` "for
+/// free", with no separate GPU-to-CPU readback step required. Wiring an actual
+/// draw function to source from this buffer rather than `MeshBatch::indirect_data`
+/// is the render-graph node's job, same as the cull dispatch itself.
+pub struct CullingIndirectArgsBuffer<M: MaterialInstanced> {
+    pub buffer: StorageBuffer<Vec<GpuCullingDrawArgs>>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for CullingIndirectArgsBuffer<M> {
+    fn default() -> Self {
+        let mut buffer = StorageBuffer::default();
+        buffer.set_label(Some("culling_indirect_args_buffer"));
+        Self {
+            buffer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// The compacted output-index buffer the cull shader writes surviving instance
+/// indices into, bound to its `compacted_indices` storage buffer. Sized to the
+/// total instance count each frame; its contents before culling are irrelevant
+/// since every live slot is written by the shader before anything reads it back.
+pub struct CompactedInstanceIndicesBuffer<M: MaterialInstanced> {
+    pub buffer: StorageBuffer<Vec<u32>>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for CompactedInstanceIndicesBuffer<M> {
+    fn default() -> Self {
+        let mut buffer = StorageBuffer::default();
+        buffer.set_label(Some("compacted_instance_indices_buffer"));
+        Self {
+            buffer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// Extracts an object-space AABB and model matrix for every instance and appends
+/// them to [`MeshCullingDataBuffer`]/[`ModelMatricesBuffer`], along with the
+/// [`InstancedMeshKey`] of the mesh it uses into [`InstanceBatchKeys`]. Walks the
+/// same queries in the same order as `prepare_mesh_batches::system` so all three
+/// buffers stay index-aligned.
+///
+/// Requires `<M::Instance as Instance>::ExtractedInstance` to expose
+/// `aabb(&self) -> (Vec3, Vec3)` (object-space center, half-extents, which the cull
+/// shader transforms by the model matrix) and `transform(&self) -> Mat4`, and
+/// `InstanceSlice` to expose the same `aabb`, in world space since slices are
+/// paired with an identity matrix below. Those
+/// types live in `render::instance` and `instance_slice`, which aren't part of this
+/// checkout, so those methods can't be authored here; this is the exact contract
+/// the real definitions need to satisfy for this system to compile.
+pub fn extract_culling_data<M: MaterialInstanced>(
+    query_instance: Query<(
+        Entity,
+        &Handle<M>,
+        &Handle<Mesh>,
+        &<M::Instance as Instance>::ExtractedInstance,
+    )>,
+    query_instance_slice: Query<(Entity, &Handle<M>, &Handle<Mesh>, &InstanceSlice)>,
+    render_meshes: Res<RenderMeshes>,
+    mut culling_data: ResMut<MeshCullingDataBuffer<M>>,
+    mut model_matrices: ResMut<ModelMatricesBuffer<M>>,
+    mut instance_batch_keys: ResMut<InstanceBatchKeys<M>>,
+) {
+    info_span!("Extract culling data").in_scope(|| {
+        let render_meshes = &render_meshes.instanced_meshes;
+
+        let data = culling_data.buffer.get_mut();
+        data.clear();
+        let matrices = model_matrices.buffer.get_mut();
+        matrices.clear();
+        instance_batch_keys.keys.clear();
+
+        for (_, _, mesh_handle, instance) in query_instance.iter() {
+            let (center, half_extents) = instance.aabb();
+            data.push(MeshCullingData::new(center, half_extents));
+            matrices.push(instance.transform());
+            instance_batch_keys
+                .keys
+                .push(render_meshes.get(mesh_handle).unwrap().key.clone());
+        }
+
+        for (_, _, mesh_handle, slice) in query_instance_slice.iter() {
+            let (center, half_extents) = slice.aabb();
+            data.push(MeshCullingData::new(center, half_extents));
+            // `InstanceSlice` represents a range of already-transformed instances
+            // rather than a single transform, so there's no one matrix to extract
+            // for it; the identity keeps this buffer aligned with the other two
+            // until slices get their own per-instance matrix source.
+            matrices.push(Mat4::IDENTITY);
+            instance_batch_keys
+                .keys
+                .push(render_meshes.get(mesh_handle).unwrap().key.clone());
+        }
+    });
+}
+
+/// Zeroes every batch's `instance_count` so the cull dispatch starts from "nothing
+/// survived yet", allocates each batch a `first_instance` range of the compacted
+/// output-index buffer sized by *instance* count (not by how many distinct-mesh
+/// draws the batch happens to contain), and builds the per-instance
+/// [`BatchIndexBuffer`] the cull shader uses to find its batch's slot.
+pub fn prepare_culling_offsets<M: MaterialInstanced>(
+    mesh_batches: Res<MeshBatches<M>>,
+    instance_keys: Res<InstanceBatchKeys<M>>,
+    mut offsets: ResMut<CullingBatchOffsets>,
+    mut batch_index_buffer: ResMut<BatchIndexBuffer<M>>,
+    mut first_instance_buffer: ResMut<FirstInstanceBuffer<M>>,
+    mut indirect_args_buffer: ResMut<CullingIndirectArgsBuffer<M>>,
+    mut compacted_indices_buffer: ResMut<CompactedInstanceIndicesBuffer<M>>,
+) {
+    info_span!("Prepare culling offsets").in_scope(|| {
+        // `MeshBatches::sorted_keys` is the single source of truth for batch order;
+        // re-deriving our own sort here could silently drift from it.
+        let sorted_keys = &mesh_batches.sorted_keys;
+
+        offsets.batch_index_by_key.clear();
+        for (batch, key) in sorted_keys.iter().enumerate() {
+            offsets.batch_index_by_key.insert(key.clone(), batch as u32);
+        }
+
+        // An instance whose key isn't in this frame's batches shouldn't happen
+        // (every instance's key comes from the same `RenderMeshes` lookup
+        // `prepare_mesh_batches::system` used to build `sorted_keys`), but unlike
+        // `prepare_uniform_offsets` this buffer feeds straight into the cull
+        // shader's `indirect_args[batch_index[i]]` atomic write, so silently
+        // reassigning it to batch 0 would corrupt a real batch's counts. Route it
+        // to a trailing dummy bucket instead, which keeps every `batch_index`
+        // entry a valid index without anything legitimate ever reading it back.
+        let unmatched_batch = sorted_keys.len() as u32;
+
+        // Count instances per batch before allocating offsets, so each batch's
+        // `first_instance` range is sized by how many instances will actually be
+        // tested against it, not by its number of distinct-mesh draws.
+        let mut instance_counts = vec![0u32; sorted_keys.len() + 1];
+        let batch_indices: Vec<u32> = instance_keys
+            .keys
+            .iter()
+            .map(|key| {
+                let batch = offsets
+                    .batch_index_by_key
+                    .get(key)
+                    .copied()
+                    .unwrap_or(unmatched_batch);
+                instance_counts[batch as usize] += 1;
+                batch
+            })
+            .collect();
+
+        offsets.first_instance = Vec::with_capacity(instance_counts.len());
+        let mut running_offset = 0u32;
+        for &count in &instance_counts {
+            offsets.first_instance.push(running_offset);
+            running_offset += count;
+        }
+
+        *batch_index_buffer.buffer.get_mut() = batch_indices;
+        *first_instance_buffer.buffer.get_mut() = offsets.first_instance.clone();
+        *compacted_indices_buffer.buffer.get_mut() = vec![0u32; running_offset as usize];
+
+        // `vertex_or_index_count`/`first_index_or_vertex`/`base_vertex_or_first_instance`
+        // come from the same batch's already-built `MeshBatch::index_data`: a batch's
+        // concatenated index/vertex buffer spans every member mesh starting at
+        // offset 0, so a single indirect-args entry can draw the whole batch once
+        // `instance_count` is known. A non-indexed draw reads `first_instance` from
+        // the fourth field, so that one carries the batch's offset there too. The
+        // unmatched-key dummy bucket has no batch to read geometry from, so its
+        // counts are left zeroed; nothing legitimate reads its entry back.
+        let indirect_args = indirect_args_buffer.buffer.get_mut();
+        indirect_args.clear();
+        for (batch, &first_instance) in offsets.first_instance.iter().enumerate() {
+            let (vertex_or_index_count, base_vertex_or_first_instance) = sorted_keys
+                .get(batch)
+                .and_then(|key| mesh_batches.mesh_batches.get(key))
+                .map(|batch| match &batch.index_data {
+                    GpuIndexBufferData::Indexed { index_count, .. } => (*index_count, 0),
+                    GpuIndexBufferData::NonIndexed { vertex_count } => {
+                        (*vertex_count, first_instance as i32)
+                    }
+                })
+                .unwrap_or((0, 0));
+
+            indirect_args.push(GpuCullingDrawArgs {
+                vertex_or_index_count,
+                instance_count: 0,
+                first_index_or_vertex: 0,
+                base_vertex_or_first_instance,
+                first_instance,
+            });
+        }
+    });
+}
+
+/// The frustum-culling compute pipeline. Built once from `shaders/frustum_cull.wgsl`
+/// and reused every frame; the render-graph node that dispatches it only has to
+/// bind [`CullingBindGroup`] and run one dispatch per frame.
+pub struct CullingPipeline {
+    pub pipeline_id: CachedComputePipelineId,
+    pub bind_group_layout: BindGroupLayout,
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+pub fn queue_culling_pipeline(
+    render_device: Res<RenderDevice>,
+    asset_server: Res<AssetServer>,
+    pipeline_cache: Res<PipelineCache>,
+) -> CullingPipeline {
+    let shader: bevy::prelude::Handle<Shader> = asset_server.load("shaders/frustum_cull.wgsl");
+
+    // Mirrors `frustum_cull.wgsl`'s `@group(0)` bindings exactly: culling AABBs,
+    // model matrices and batch_index/first_instance are read-only; indirect_args
+    // and compacted_indices are read_write since the shader mutates them in place.
+    let bind_group_layout = render_device.create_bind_group_layout(
+        Some("frustum_cull_bind_group_layout"),
+        &[
+            storage_entry(0, true),
+            storage_entry(1, true),
+            storage_entry(2, false),
+            storage_entry(3, false),
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            storage_entry(5, true),
+            storage_entry(6, true),
+        ],
+    );
+
+    let pipeline_id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: Some("frustum_cull_pipeline".into()),
+        layout: vec![bind_group_layout.clone()],
+        push_constant_ranges: vec![],
+        shader,
+        shader_defs: vec![],
+        entry_point: "cull".into(),
+    });
+
+    CullingPipeline {
+        pipeline_id,
+        bind_group_layout,
+    }
+}
+
+/// The view-projection matrix the cull shader tests instance AABBs against.
+/// Populated by a per-view extraction system elsewhere in the render app (the
+/// same extraction that already runs for the regular camera view); defaults to
+/// identity so this buffer is never left uninitialized between extractions.
+pub struct CullingViewProj<M: MaterialInstanced> {
+    pub buffer: UniformBuffer<Mat4>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: MaterialInstanced> Default for CullingViewProj<M> {
+    fn default() -> Self {
+        let mut buffer = UniformBuffer::from(Mat4::IDENTITY);
+        buffer.set_label(Some("culling_view_proj_buffer"));
+        Self {
+            buffer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// The bind group a render-graph node binds before dispatching `CullingPipeline`.
+/// Rebuilt whenever the underlying buffers are re-created (they're resized most
+/// frames, which for `StorageBuffer` means a new `wgpu::Buffer`), so the bind
+/// group can never point at a stale/dropped buffer.
+pub struct CullingBindGroup<M: MaterialInstanced> {
+    pub bind_group: BindGroup,
+    _phantom: PhantomData<M>,
+}
+
+/// Builds [`CullingBindGroup`] from this frame's culling buffers, mirroring
+/// `frustum_cull.wgsl`'s `@group(0)` bindings in the same order as
+/// [`queue_culling_pipeline`]'s layout.
+pub fn prepare_culling_bind_group<M: MaterialInstanced>(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    pipeline: Res<CullingPipeline>,
+    culling_data: Res<MeshCullingDataBuffer<M>>,
+    model_matrices: Res<ModelMatricesBuffer<M>>,
+    indirect_args: Res<CullingIndirectArgsBuffer<M>>,
+    compacted_indices: Res<CompactedInstanceIndicesBuffer<M>>,
+    view_proj: Res<CullingViewProj<M>>,
+    batch_index: Res<BatchIndexBuffer<M>>,
+    first_instance: Res<FirstInstanceBuffer<M>>,
+) {
+    info_span!("Prepare culling bind group").in_scope(|| {
+        let Some(culling_data_binding) = culling_data.buffer.binding() else {
+            return;
+        };
+        let Some(model_matrices_binding) = model_matrices.buffer.binding() else {
+            return;
+        };
+        let Some(indirect_args_binding) = indirect_args.buffer.binding() else {
+            return;
+        };
+        let Some(compacted_indices_binding) = compacted_indices.buffer.binding() else {
+            return;
+        };
+        let Some(view_proj_binding) = view_proj.buffer.binding() else {
+            return;
+        };
+        let Some(batch_index_binding) = batch_index.buffer.binding() else {
+            return;
+        };
+        let Some(first_instance_binding) = first_instance.buffer.binding() else {
+            return;
+        };
+
+        let bind_group = render_device.create_bind_group(
+            Some("frustum_cull_bind_group"),
+            &pipeline.bind_group_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::Buffer(culling_data_binding),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Buffer(model_matrices_binding),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::Buffer(indirect_args_binding),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::Buffer(compacted_indices_binding),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::Buffer(view_proj_binding),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::Buffer(batch_index_binding),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: BindingResource::Buffer(first_instance_binding),
+                },
+            ],
+        );
+
+        commands.insert_resource(CullingBindGroup::<M> {
+            bind_group,
+            _phantom: PhantomData,
+        });
+    });
+}
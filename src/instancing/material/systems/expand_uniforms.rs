@@ -0,0 +1,300 @@
+use std::marker::PhantomData;
+
+use hashbrown::HashMap;
+
+use bevy::{
+    prelude::{
+        info_span, AssetServer, Commands, Handle, Mat3, Mat4, Mesh, Query, Res, ResMut, Shader,
+    },
+    render::{
+        render_resource::{
+            BindGroup, BindGroupEntry, BindGroupLayout, BindGroupLayoutEntry, BindingResource,
+            BindingType, BufferBindingType, CachedComputePipelineId, ComputePipelineDescriptor,
+            PipelineCache, ShaderStages, ShaderType, StorageBuffer,
+        },
+        renderer::RenderDevice,
+    },
+};
+
+use crate::instancing::{
+    instance_slice::InstanceSlice,
+    material::{
+        material_instanced::MaterialInstanced,
+        plugin::{InstancedMeshKey, RenderMeshes},
+    },
+    render::instance::Instance,
+};
+
+/// The minimal per-instance data extracted on the CPU: an affine transform
+/// (three `vec4` rows, 48 bytes) plus flags, 64 bytes in all. A compute pass expands
+/// each of these into the full instance uniform the shader consumes, instead of
+/// computing the normal matrix on the CPU every frame.
+///
+/// Gated behind the `gpu_uniform_expansion` feature; without it, instances are
+/// still expanded to full uniforms on the CPU at extract time.
+#[cfg(feature = "gpu_uniform_expansion")]
+#[derive(ShaderType, Clone, Copy)]
+pub struct MeshInputUniform {
+    pub transform_row_0: bevy::prelude::Vec4,
+    pub transform_row_1: bevy::prelude::Vec4,
+    pub transform_row_2: bevy::prelude::Vec4,
+    pub flags: u32,
+    pub _padding: [u32; 3],
+}
+
+#[cfg(feature = "gpu_uniform_expansion")]
+impl MeshInputUniform {
+    pub fn new(transform: Mat4, flags: u32) -> Self {
+        Self {
+            transform_row_0: transform.row(0),
+            transform_row_1: transform.row(1),
+            transform_row_2: transform.row(2),
+            flags,
+            _padding: [0; 3],
+        }
+    }
+}
+
+/// The full per-instance uniform the draw shader consumes, expanded on the GPU
+/// from a [`MeshInputUniform`]. `inverse_transpose_model` is computed in the
+/// expansion compute shader so normals transform correctly without a CPU matrix
+/// inverse every frame.
+#[cfg(feature = "gpu_uniform_expansion")]
+#[derive(ShaderType, Clone, Copy)]
+pub struct ExpandedInstanceUniform {
+    pub model: Mat4,
+    pub inverse_transpose_model: Mat3,
+    pub flags: u32,
+    pub _padding: [u32; 3],
+}
+
+/// Per-`MaterialInstanced` storage of [`MeshInputUniform`]s awaiting expansion,
+/// and the buffer the expansion compute pass writes full uniforms into.
+#[cfg(feature = "gpu_uniform_expansion")]
+pub struct MeshInputUniformBuffers<M: MaterialInstanced> {
+    pub input: StorageBuffer<Vec<MeshInputUniform>>,
+    pub expanded: StorageBuffer<Vec<ExpandedInstanceUniform>>,
+    _phantom: PhantomData<M>,
+}
+
+#[cfg(feature = "gpu_uniform_expansion")]
+impl<M: MaterialInstanced> Default for MeshInputUniformBuffers<M> {
+    fn default() -> Self {
+        let mut input = StorageBuffer::default();
+        input.set_label(Some("mesh_input_uniform_buffer"));
+
+        let mut expanded = StorageBuffer::default();
+        expanded.set_label(Some("expanded_instance_uniform_buffer"));
+
+        Self {
+            input,
+            expanded,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// The base offset into the expanded uniform buffer allocated to each batch.
+///
+/// Ideally `MeshBatch` itself would carry this so a batch's draw function can read
+/// its base offset directly instead of going through a side table keyed by
+/// `InstancedMeshKey`; `MeshBatch` is defined in `material::plugin`, a file this
+/// checkout doesn't include, so this stays a standalone resource for now rather
+/// than guessing at a field to add to a struct whose definition isn't visible here.
+#[cfg(feature = "gpu_uniform_expansion")]
+#[derive(Default)]
+pub struct UniformBatchOffsets {
+    pub base_offset: HashMap<InstancedMeshKey, u32>,
+}
+
+/// Per-instance `InstancedMeshKey`, 1:1 aligned with [`MeshInputUniformBuffers::input`],
+/// so `prepare_uniform_offsets` can size each batch's range by actual instance
+/// count instead of by how many distinct meshes the batch happens to contain.
+#[cfg(feature = "gpu_uniform_expansion")]
+#[derive(Default)]
+pub struct InstanceBatchKeys<M: MaterialInstanced> {
+    pub keys: Vec<InstancedMeshKey>,
+    _phantom: PhantomData<M>,
+}
+
+/// Extracts the minimal per-instance transform + flags into the batch's
+/// [`MeshInputUniformBuffers::input`], one entry per live instance this frame.
+///
+/// Requires `<M::Instance as Instance>::ExtractedInstance` to expose
+/// `transform(&self) -> Mat4` and `flags(&self) -> u32`. That type lives in
+/// `render::instance`, which isn't part of this checkout, so those methods can't
+/// be authored here; this is the exact contract the real definition needs to
+/// satisfy for this system to compile.
+#[cfg(feature = "gpu_uniform_expansion")]
+pub fn extract_mesh_input_uniforms<M: MaterialInstanced>(
+    query_instance: Query<(
+        &Handle<M>,
+        &Handle<Mesh>,
+        &<M::Instance as Instance>::ExtractedInstance,
+    )>,
+    render_meshes: Res<RenderMeshes>,
+    mut buffers: ResMut<MeshInputUniformBuffers<M>>,
+    mut instance_keys: ResMut<InstanceBatchKeys<M>>,
+) {
+    info_span!("Extract mesh input uniforms").in_scope(|| {
+        let render_meshes = &render_meshes.instanced_meshes;
+
+        let input = buffers.input.get_mut();
+        input.clear();
+        instance_keys.keys.clear();
+
+        for (_, mesh_handle, instance) in query_instance.iter() {
+            input.push(MeshInputUniform::new(instance.transform(), instance.flags()));
+            instance_keys
+                .keys
+                .push(render_meshes.get(mesh_handle).unwrap().key.clone());
+        }
+    });
+}
+
+/// Allocates each batch a contiguous `base_offset` range of the expanded uniform
+/// buffer, sized by *instance* count (not by how many distinct-mesh draws the
+/// batch happens to contain), in the same key order `system<M>` uses to build
+/// `mesh_batches`.
+#[cfg(feature = "gpu_uniform_expansion")]
+pub fn prepare_uniform_offsets<M: MaterialInstanced>(
+    mesh_batches: Res<super::prepare_mesh_batches::MeshBatches<M>>,
+    instance_keys: Res<InstanceBatchKeys<M>>,
+    mut offsets: ResMut<UniformBatchOffsets>,
+) {
+    info_span!("Prepare uniform offsets").in_scope(|| {
+        // `MeshBatches::sorted_keys` is the single source of truth for batch order;
+        // re-deriving our own sort here could silently drift from it.
+        let sorted_keys = &mesh_batches.sorted_keys;
+
+        let mut batch_index = HashMap::with_capacity(sorted_keys.len());
+        for (index, key) in sorted_keys.iter().enumerate() {
+            batch_index.insert(key.clone(), index as u32);
+        }
+
+        let mut instance_counts = vec![0u32; sorted_keys.len()];
+        for key in &instance_keys.keys {
+            if let Some(&index) = batch_index.get(key) {
+                instance_counts[index as usize] += 1;
+            }
+        }
+
+        offsets.base_offset.clear();
+        let mut running_offset = 0u32;
+        for (key, count) in sorted_keys.iter().zip(instance_counts.iter()) {
+            offsets.base_offset.insert(key.clone(), running_offset);
+            running_offset += count;
+        }
+    });
+}
+
+/// The uniform-expansion compute pipeline, built once from
+/// `shaders/expand_mesh_uniforms.wgsl`.
+#[cfg(feature = "gpu_uniform_expansion")]
+pub struct ExpandUniformsPipeline {
+    pub pipeline_id: CachedComputePipelineId,
+    pub bind_group_layout: BindGroupLayout,
+}
+
+#[cfg(feature = "gpu_uniform_expansion")]
+pub fn queue_expand_uniforms_pipeline(
+    render_device: Res<RenderDevice>,
+    asset_server: Res<AssetServer>,
+    pipeline_cache: Res<PipelineCache>,
+) -> ExpandUniformsPipeline {
+    let shader: bevy::prelude::Handle<Shader> =
+        asset_server.load("shaders/expand_mesh_uniforms.wgsl");
+
+    // Mirrors `expand_mesh_uniforms.wgsl`'s `@group(0)` bindings exactly:
+    // `mesh_input` is read-only, `expanded` is read_write since the shader writes
+    // every element of it in place.
+    let bind_group_layout = render_device.create_bind_group_layout(
+        Some("expand_mesh_uniforms_bind_group_layout"),
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    );
+
+    let pipeline_id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: Some("expand_mesh_uniforms_pipeline".into()),
+        layout: vec![bind_group_layout.clone()],
+        push_constant_ranges: vec![],
+        shader,
+        shader_defs: vec![],
+        entry_point: "expand".into(),
+    });
+
+    ExpandUniformsPipeline {
+        pipeline_id,
+        bind_group_layout,
+    }
+}
+
+/// The bind group a render-graph node binds before dispatching
+/// [`ExpandUniformsPipeline`]. Rebuilt whenever the underlying buffers are
+/// re-created (they're resized most frames, which for `StorageBuffer` means a new
+/// `wgpu::Buffer`), so the bind group can never point at a stale/dropped buffer.
+#[cfg(feature = "gpu_uniform_expansion")]
+pub struct ExpandUniformsBindGroup<M: MaterialInstanced> {
+    pub bind_group: BindGroup,
+    _phantom: PhantomData<M>,
+}
+
+/// Builds [`ExpandUniformsBindGroup`] from this frame's uniform buffers, mirroring
+/// `expand_mesh_uniforms.wgsl`'s `@group(0)` bindings in the same order as
+/// [`queue_expand_uniforms_pipeline`]'s layout.
+#[cfg(feature = "gpu_uniform_expansion")]
+pub fn prepare_expand_uniforms_bind_group<M: MaterialInstanced>(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    pipeline: Res<ExpandUniformsPipeline>,
+    buffers: Res<MeshInputUniformBuffers<M>>,
+) {
+    info_span!("Prepare expand uniforms bind group").in_scope(|| {
+        let Some(input_binding) = buffers.input.binding() else {
+            return;
+        };
+        let Some(expanded_binding) = buffers.expanded.binding() else {
+            return;
+        };
+
+        let bind_group = render_device.create_bind_group(
+            Some("expand_mesh_uniforms_bind_group"),
+            &pipeline.bind_group_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::Buffer(input_binding),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Buffer(expanded_binding),
+                },
+            ],
+        );
+
+        commands.insert_resource(ExpandUniformsBindGroup::<M> {
+            bind_group,
+            _phantom: PhantomData,
+        });
+    });
+}
@@ -1,12 +1,16 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
-    marker::PhantomData, ops::{Deref, DerefMut},
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::{BuildHasherDefault, Hash, Hasher},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
 };
 
+use hashbrown::{HashMap, HashSet};
+
 use crate::prelude::{DrawIndexedIndirect, DrawIndirect};
 use bevy::{
     prelude::{debug, default, info_span, Entity, Handle, Mesh, Query, Res, ResMut},
-    render::mesh::Indices,
+    render::{mesh::Indices, render_resource::IndexFormat},
 };
 
 use crate::instancing::{
@@ -18,13 +22,61 @@ use crate::instancing::{
     render::instance::Instance,
 };
 
+/// A generic multiply-rotate hash (the standard "FxHash" construction): fast for
+/// the small, fixed-shape keys the batching hot path looks up by (`Entity`,
+/// `InstancedMeshKey`, `Handle<Mesh>`), and cheap enough that hand-rolling it here
+/// beats pulling in a dependency for something this small. Unlike a hasher
+/// special-cased to `u64`, `write` is genuinely generic, so it works for any
+/// `#[derive(Hash)]` key, not just ids that are already a bare `u64`.
+pub struct FastHasher(u64);
+
+impl Default for FastHasher {
+    fn default() -> Self {
+        Self(0x517cc1b727220a95)
+    }
+}
+
+impl Hasher for FastHasher {
+    fn write(&mut self, mut bytes: &[u8]) {
+        while bytes.len() >= 8 {
+            self.write_u64(u64::from_ne_bytes(bytes[..8].try_into().unwrap()));
+            bytes = &bytes[8..];
+        }
+        if !bytes.is_empty() {
+            let mut buf = [0u8; 8];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            self.write_u64(u64::from_ne_bytes(buf));
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = (self.0.rotate_left(5) ^ i).wrapping_mul(0x517cc1b727220a95);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub type FastHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FastHasher>>;
+pub type FastHashSet<T> = HashSet<T, BuildHasherDefault<FastHasher>>;
+
 pub struct MeshBatches<M: MaterialInstanced> {
-    pub mesh_batches: BTreeMap<InstancedMeshKey, MeshBatch>,
+    pub mesh_batches: FastHashMap<InstancedMeshKey, MeshBatch>,
+    /// `mesh_batches`'s keys in sorted order, kept alongside it so consumers that
+    /// need a deterministic walk (e.g. `prepare_culling_offsets`) can rely on this
+    /// instead of `mesh_batches`'s own hash-based order, which isn't specified to
+    /// stay stable across runs or resizes, and instead of re-deriving their own
+    /// sort of the same keys every frame.
+    pub sorted_keys: Vec<InstancedMeshKey>,
+    /// Content hash of each batch's mesh-handle set as of the last time it was
+    /// (re)generated, so an unchanged batch can be recognised and reused as-is.
+    membership_hash: FastHashMap<InstancedMeshKey, u64>,
     _phantom: PhantomData<M>,
 }
 
 impl<M: MaterialInstanced> Deref for MeshBatches<M> {
-    type Target = BTreeMap<InstancedMeshKey, MeshBatch>;
+    type Target = FastHashMap<InstancedMeshKey, MeshBatch>;
 
     fn deref(&self) -> &Self::Target {
         &self.mesh_batches
@@ -41,11 +93,131 @@ impl<M: MaterialInstanced> Default for MeshBatches<M> {
     fn default() -> Self {
         Self {
             mesh_batches: Default::default(),
+            sorted_keys: Default::default(),
+            membership_hash: Default::default(),
             _phantom: Default::default(),
         }
     }
 }
 
+impl<M: MaterialInstanced> MeshBatches<M> {
+    /// Walks batches in the same deterministic key order every frame, regardless
+    /// of `mesh_batches`'s own hash-based iteration order.
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (&InstancedMeshKey, &MeshBatch)> {
+        self.sorted_keys
+            .iter()
+            .filter_map(|key| self.mesh_batches.get(key).map(|batch| (key, batch)))
+    }
+}
+
+/// Hashes a batch's mesh-handle membership. `BTreeSet` already iterates in a
+/// stable, deterministic order, so a plain sequential hash is enough to detect
+/// whether a batch's membership changed since the last frame.
+fn hash_membership(meshes: &BTreeSet<Handle<Mesh>>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    meshes.len().hash(&mut hasher);
+    for mesh in meshes {
+        mesh.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Whether a batch's previous-frame `MeshBatch` can be reused wholesale: its
+/// mesh-handle membership hash must be unchanged, and nothing in `RenderMeshes`
+/// can have changed this frame (see the caveat on `render_meshes_changed` in
+/// `system` about why that second check is conservative, not per-mesh).
+fn should_reuse_batch(render_meshes_changed: bool, cached_hash: Option<&u64>, membership_hash: u64) -> bool {
+    !render_meshes_changed && cached_hash == Some(&membership_hash)
+}
+
+/// Picks the index width a batch's concatenated index buffer should use: `Uint32`
+/// if any member mesh is already `U32`, or if the batch's accumulated vertex
+/// count reaches `u16::MAX` even though every member is `U16`. A U16
+/// index's valid range is bounded by the highest *rebased index value* it can
+/// hold, i.e. `base_index + original_index`, and `base_index` accumulates by
+/// vertex count (see `system`'s "Index data" fold) — not by how many indices
+/// a mesh has — so the guard must be on total vertices, not total indices.
+fn target_index_format(any_u32: bool, total_vertex_count: u32) -> IndexFormat {
+    if any_u32 || total_vertex_count >= u16::MAX as u32 {
+        IndexFormat::Uint32
+    } else {
+        IndexFormat::Uint16
+    }
+}
+
+/// Widens and rebases one mesh's indices to `target_index_format`, so that
+/// concatenating it after `base_index` prior vertices produces valid indices
+/// into the batch's combined vertex buffer.
+fn widen_and_rebase_indices(
+    target_index_format: IndexFormat,
+    indices: &Indices,
+    base_index: u32,
+) -> Indices {
+    match (target_index_format, indices) {
+        (IndexFormat::Uint32, Indices::U16(idx)) => {
+            Indices::U32(idx.iter().map(|i| base_index + *i as u32).collect())
+        }
+        (IndexFormat::Uint32, Indices::U32(idx)) => {
+            Indices::U32(idx.iter().map(|i| base_index + *i).collect())
+        }
+        (IndexFormat::Uint16, Indices::U16(idx)) => {
+            Indices::U16(idx.iter().map(|i| base_index as u16 + *i).collect())
+        }
+        (IndexFormat::Uint16, Indices::U32(_)) => unreachable!(
+            "target_index_format is only Uint16 when every mesh in the batch is U16"
+        ),
+    }
+}
+
+/// Folds one mesh's index data into a batch's accumulated [`GpuIndexBufferData`],
+/// widening it to `target_index_format` first so a `U16` mesh sharing a batch
+/// with a `U32` mesh no longer panics.
+fn accumulate_index_buffer(
+    acc: Option<GpuIndexBufferData>,
+    mesh_index_data: &GpuIndexBufferData,
+    base_index: u32,
+    target_index_format: IndexFormat,
+) -> GpuIndexBufferData {
+    match mesh_index_data {
+        GpuIndexBufferData::Indexed { indices, index_count, .. } => {
+            let rebased = widen_and_rebase_indices(target_index_format, indices, base_index);
+
+            match acc {
+                Some(GpuIndexBufferData::Indexed {
+                    indices: acc_indices,
+                    index_count: acc_index_count,
+                    ..
+                }) => GpuIndexBufferData::Indexed {
+                    indices: match (acc_indices, rebased) {
+                        (Indices::U16(lhs), Indices::U16(rhs)) => {
+                            Indices::U16(lhs.into_iter().chain(rhs).collect())
+                        }
+                        (Indices::U32(lhs), Indices::U32(rhs)) => {
+                            Indices::U32(lhs.into_iter().chain(rhs).collect())
+                        }
+                        _ => unreachable!("widening above already unified index formats"),
+                    },
+                    index_count: index_count + acc_index_count,
+                    index_format: target_index_format,
+                },
+                None => GpuIndexBufferData::Indexed {
+                    indices: rebased,
+                    index_count: *index_count,
+                    index_format: target_index_format,
+                },
+                _ => panic!("Mismatched GpuIndexBufferData"),
+            }
+        }
+        GpuIndexBufferData::NonIndexed { vertex_count } => match acc {
+            Some(GpuIndexBufferData::NonIndexed { vertex_count: acc_vertex_count }) => {
+                GpuIndexBufferData::NonIndexed { vertex_count: vertex_count + acc_vertex_count }
+            }
+            None => GpuIndexBufferData::NonIndexed { vertex_count: *vertex_count },
+            _ => panic!("Mismatched GpuIndexBufferData"),
+        },
+    }
+}
+
 pub fn system<M: MaterialInstanced>(
     render_meshes: Res<RenderMeshes>,
     query_instance: Query<(
@@ -59,17 +231,29 @@ pub fn system<M: MaterialInstanced>(
 ) {
     debug!("{}", std::any::type_name::<M>());
 
+    // Whole-resource, not per-mesh: `RenderMeshes` doesn't expose per-entry change
+    // ticks (it's defined in `material::plugin`, a file outside this checkout), so
+    // there's no way from here to tell "this frame's `RenderMeshes` write touched
+    // member X" from "it touched some other mesh entirely". If `RenderMeshes` is
+    // rebuilt via `ResMut` every extract frame, this reads `true` every frame and
+    // the membership-hash check below never actually gets to reuse a batch. A true
+    // per-mesh dirty flag needs that granularity added to `RenderMeshes` itself.
+    let render_meshes_changed = render_meshes.is_changed();
     let render_meshes = &render_meshes.instanced_meshes;
 
-    // Sort meshes into batches by their InstancedMeshKey
+    // Sort meshes into batches by their InstancedMeshKey. `query_instance` and
+    // `query_instance_slice` match disjoint component types (`Instance` vs.
+    // `InstanceSlice`), so no entity can appear in both and there's nothing to
+    // dedupe here.
     let keyed_meshes = info_span!("Key meshes").in_scope(|| {
-        let mut keyed_meshes = BTreeMap::<InstancedMeshKey, BTreeSet<Handle<Mesh>>>::new();
+        let mut keyed_meshes = FastHashMap::<InstancedMeshKey, FastHashSet<Handle<Mesh>>>::default();
+
         for mesh_handle in query_instance
             .iter()
             .map(|(_, _, mesh, _)| mesh)
             .chain(query_instance_slice.iter().map(|(_, _, mesh, _)| mesh))
         {
-            let mesh = render_meshes.get(&mesh_handle).unwrap();
+            let mesh = render_meshes.get(mesh_handle).unwrap();
             keyed_meshes
                 .entry(mesh.key.clone())
                 .or_default()
@@ -78,11 +262,73 @@ pub fn system<M: MaterialInstanced>(
         keyed_meshes
     });
 
-    // Generate vertex, index, and indirect data for each batch
+    // Collect into a sorted `Vec<InstancedMeshKey>` once so the `base_index`
+    // accumulation across meshes below stays stable frame-to-frame, even though
+    // the map itself doesn't iterate in key order. This is the *pre-widening* key
+    // (see the widening step below); `MeshBatches::sorted_keys` is set separately,
+    // after widening, so it matches what `mesh_batches.mesh_batches` is actually
+    // keyed by.
+    let mut sorted_keys = keyed_meshes.keys().cloned().collect::<Vec<_>>();
+    sorted_keys.sort();
+
+    // A batch is only reusable if its mesh-handle membership hash is unchanged
+    // *and* nothing in `RenderMeshes` changed this frame (e.g. a member mesh was
+    // reloaded with new geometry). `RenderMeshes` doesn't track per-mesh versions,
+    // so this is conservative: any change anywhere invalidates every unchanged-
+    // membership batch's cache, not just the batch the changed mesh belongs to.
+    let mut new_membership_hash = FastHashMap::with_capacity_and_hasher(sorted_keys.len(), Default::default());
+
+    // Generate vertex, index, and indirect data for each batch, reusing the
+    // previous frame's `MeshBatch` wholesale when its membership didn't change.
     mesh_batches.mesh_batches = info_span!("Batch meshes").in_scope(|| {
-        keyed_meshes
+        sorted_keys
             .into_iter()
-            .map(|(key, meshes)| {
+            .map(|key| {
+                // Walk the batch's meshes in sorted handle order, not the hash set's
+                // order, so `base_index` accumulates the same way every frame.
+                let batch_meshes: BTreeSet<Handle<Mesh>> =
+                    keyed_meshes[&key].iter().cloned().collect();
+                let meshes = &batch_meshes;
+                let membership_hash = hash_membership(&batch_meshes);
+
+                // Decide up front whether this batch needs widening to `Uint32`:
+                // any `U32` member forces it, and so does a total vertex count
+                // that reaches `u16::MAX` even if every member is `U16`.
+                // This has to happen *before* the cache check below and be
+                // reflected into `key` right away, so a batch's cache entry is
+                // looked up and stored under the same (possibly-widened) key
+                // every frame, not just the frame it was first built in.
+                let any_u32 = meshes.iter().any(|mesh| {
+                    matches!(
+                        &render_meshes.get(mesh).unwrap().index_buffer_data,
+                        GpuIndexBufferData::Indexed { indices: Indices::U32(_), .. }
+                    )
+                });
+                let total_vertex_count: u32 = meshes
+                    .iter()
+                    .map(|mesh| render_meshes.get(mesh).unwrap().vertex_count)
+                    .sum();
+                let target_index_format = target_index_format(any_u32, total_vertex_count);
+
+                // Reflect the widened format in the key so the indirect buffer and
+                // the GPU index buffer agree on what width they're reading.
+                let mut key = key;
+                if key.index_format.is_some() {
+                    key.index_format = Some(target_index_format);
+                }
+
+                new_membership_hash.insert(key.clone(), membership_hash);
+
+                if should_reuse_batch(
+                    render_meshes_changed,
+                    mesh_batches.membership_hash.get(&key),
+                    membership_hash,
+                ) {
+                    if let Some(cached) = mesh_batches.mesh_batches.remove(&key) {
+                        return (key, cached);
+                    }
+                }
+
                 let vertex_data = info_span!("Vertex data").in_scope(|| {
                     meshes
                         .iter()
@@ -97,59 +343,12 @@ pub fn system<M: MaterialInstanced>(
                     meshes.iter().fold(None, |acc, mesh| {
                         let mesh = render_meshes.get(mesh).unwrap();
 
-                        let out = match &mesh.index_buffer_data {
-                            GpuIndexBufferData::Indexed {
-                                indices,
-                                index_count,
-                                index_format,
-                            } => Some(match acc {
-                                Some(GpuIndexBufferData::Indexed {
-                                    indices: acc_indices,
-                                    index_count: acc_index_count,
-                                    ..
-                                }) => GpuIndexBufferData::Indexed {
-                                    indices: match (acc_indices, indices) {
-                                        (Indices::U16(lhs), Indices::U16(rhs)) => Indices::U16(
-                                            lhs.iter()
-                                                .copied()
-                                                .chain(
-                                                    rhs.iter().map(|idx| base_index as u16 + *idx),
-                                                )
-                                                .collect(),
-                                        ),
-                                        (Indices::U32(lhs), Indices::U32(rhs)) => Indices::U32(
-                                            lhs.iter()
-                                                .copied()
-                                                .chain(
-                                                    rhs.iter().map(|idx| base_index as u32 + *idx),
-                                                )
-                                                .collect(),
-                                        ),
-                                        _ => panic!("Mismatched index format"),
-                                    },
-
-                                    index_count: index_count + acc_index_count,
-                                    index_format: *index_format,
-                                },
-                                None => GpuIndexBufferData::Indexed {
-                                    indices: indices.clone(),
-                                    index_count: *index_count,
-                                    index_format: *index_format,
-                                },
-                                _ => panic!("Mismatched GpuIndexBufferData"),
-                            }),
-                            GpuIndexBufferData::NonIndexed { vertex_count } => Some(match acc {
-                                Some(GpuIndexBufferData::NonIndexed {
-                                    vertex_count: acc_vertex_count,
-                                }) => GpuIndexBufferData::NonIndexed {
-                                    vertex_count: vertex_count + acc_vertex_count,
-                                },
-                                None => GpuIndexBufferData::NonIndexed {
-                                    vertex_count: *vertex_count,
-                                },
-                                _ => panic!("Mismatched GpuIndexBufferData"),
-                            }),
-                        };
+                        let out = Some(accumulate_index_buffer(
+                            acc,
+                            &mesh.index_buffer_data,
+                            base_index,
+                            target_index_format,
+                        ));
 
                         base_index += mesh.vertex_count;
 
@@ -199,9 +398,9 @@ pub fn system<M: MaterialInstanced>(
                     });
 
                 (
-                    key.clone(),
+                    key,
                     MeshBatch {
-                        meshes,
+                        meshes: batch_meshes,
                         vertex_data,
                         index_data,
                         indirect_data,
@@ -210,4 +409,113 @@ pub fn system<M: MaterialInstanced>(
             })
             .collect()
     });
+
+    mesh_batches.membership_hash = new_membership_hash;
+
+    // Derived from the final map's own keys (i.e. post-widening), not from the
+    // pre-widening `sorted_keys` above: widening can change a key's `index_format`
+    // partway through the loop, and `mesh_batches.mesh_batches` is keyed by the
+    // widened version. Sorting the pre-widening keys instead would let this list
+    // silently drift from what the map is actually keyed by.
+    mesh_batches.sorted_keys = mesh_batches.mesh_batches.keys().cloned().collect();
+    mesh_batches.sorted_keys.sort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_u16_under_limit_stays_u16() {
+        assert_eq!(target_index_format(false, u16::MAX as u32 - 1), IndexFormat::Uint16);
+    }
+
+    #[test]
+    fn any_u32_member_forces_u32() {
+        assert_eq!(target_index_format(true, 3), IndexFormat::Uint32);
+    }
+
+    #[test]
+    fn all_u16_at_the_overflow_boundary_widens_to_u32() {
+        assert_eq!(target_index_format(false, u16::MAX as u32), IndexFormat::Uint32);
+        assert_eq!(target_index_format(false, u16::MAX as u32 + 1), IndexFormat::Uint32);
+    }
+
+    // `system<M>` is generic over `MaterialInstanced` and reads from ECS queries,
+    // so it can't be driven directly in a unit test without a full `World`; this
+    // exercises the exact accumulation path it calls (`target_index_format` then
+    // `accumulate_index_buffer` per mesh) the way `system<M>` drives it across a
+    // batch, so the widened-format regression chunk0-4 fixed can't come back.
+    #[test]
+    fn mixed_u16_u32_batch_widens_and_rebases_to_u32() {
+        let u16_mesh = GpuIndexBufferData::Indexed {
+            indices: Indices::U16(vec![0, 1, 2]),
+            index_count: 3,
+            index_format: IndexFormat::Uint16,
+        };
+        let u32_mesh = GpuIndexBufferData::Indexed {
+            indices: Indices::U32(vec![0, 1, 2]),
+            index_count: 3,
+            index_format: IndexFormat::Uint32,
+        };
+
+        let target_index_format = target_index_format(true, 6);
+        assert_eq!(target_index_format, IndexFormat::Uint32);
+
+        let acc = accumulate_index_buffer(None, &u16_mesh, 0, target_index_format);
+        let acc = accumulate_index_buffer(Some(acc), &u32_mesh, 3, target_index_format);
+
+        match acc {
+            GpuIndexBufferData::Indexed {
+                indices: Indices::U32(indices),
+                index_count,
+                index_format,
+            } => {
+                assert_eq!(index_format, IndexFormat::Uint32);
+                assert_eq!(index_count, 6);
+                assert_eq!(indices, vec![0, 1, 2, 3, 4, 5]);
+            }
+            other => panic!("expected a widened Uint32 index buffer, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn membership_hash_is_deterministic_but_content_sensitive() {
+        let handle_a = Handle::<Mesh>::default();
+        let set = BTreeSet::from([handle_a.clone()]);
+        let empty = BTreeSet::new();
+
+        assert_eq!(hash_membership(&set), hash_membership(&set.clone()));
+        assert_ne!(hash_membership(&set), hash_membership(&empty));
+    }
+
+    // Exercises the actual caching decision `system<M>` makes every frame (not
+    // just its inputs in isolation): an unchanged membership hash with no
+    // `RenderMeshes` change reuses the cached batch; either a membership change or
+    // a `RenderMeshes` change invalidates it.
+    #[test]
+    fn should_reuse_batch_only_when_membership_and_render_meshes_are_both_unchanged() {
+        let cached_hash = 42u64;
+
+        assert!(should_reuse_batch(false, Some(&cached_hash), cached_hash));
+        assert!(!should_reuse_batch(true, Some(&cached_hash), cached_hash));
+        assert!(!should_reuse_batch(false, Some(&cached_hash), cached_hash + 1));
+        assert!(!should_reuse_batch(false, None, cached_hash));
+    }
+
+    #[test]
+    fn fast_hasher_is_generic_over_arbitrary_keys() {
+        use std::hash::BuildHasher;
+
+        let build = BuildHasherDefault::<FastHasher>::default();
+
+        let hash_of = |value: &str| {
+            let mut hasher = build.build_hasher();
+            value.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        assert_eq!(hash_of("chunk0"), hash_of("chunk0"));
+        assert_ne!(hash_of("chunk0"), hash_of("chunk1"));
+    }
 }